@@ -5,19 +5,210 @@
 #![deny(missing_docs)]
 #![allow(unknown_lints, inline_always)]
 
+pub mod sync;
+
 use std::ptr;
+use std::mem;
 use std::marker::PhantomData;
 use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
 
 enum State<T> {
     Unevaluated,
     InProgress,
     Evaluated(T),
+    Poisoned,
+}
+
+/// A single-assignment cell, set either directly via [`set`](OnceCell::set) or lazily via
+/// [`get_or_try_init`](OnceCell::get_or_try_init), driven by the [`LazyInit`] trait rather
+/// than a closure.
+///
+/// This is the storage [`Lazy`] is built on; reach for it directly when the value should
+/// sometimes be supplied externally instead of always being computed by `init()`.
+pub struct OnceCell<T> {
+    inner: UnsafeCell<State<T>>
+}
+
+impl<T> Default for OnceCell<T> {
+    #[inline(always)]
+    fn default() -> OnceCell<T> {
+        OnceCell::new()
+    }
+}
+
+impl<T> OnceCell<T> {
+    /// Create a new, uninitialized cell.
+    #[inline(always)]
+    pub fn new() -> OnceCell<T> {
+        OnceCell {
+            inner: UnsafeCell::new(State::Unevaluated)
+        }
+    }
+
+    /// Sets the contents of this cell to `value`.
+    ///
+    /// Returns `Err(value)` if the cell was already initialized, in progress, or poisoned.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        unsafe {
+            match *self.inner.get() {
+                State::Unevaluated => {
+                    *self.inner.get() = State::Evaluated(value);
+                    Ok(())
+                },
+                _ => Err(value)
+            }
+        }
+    }
+
+    /// Forcibly overwrites the cell's contents, regardless of its previous state.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the evaluation is already in progress.
+    fn force_set(&self, value: T) {
+        unsafe {
+            match *self.inner.get() {
+                State::InProgress => panic!("Lazy evaluation called from itself."),
+                _ => *self.inner.get() = State::Evaluated(value)
+            }
+        }
+    }
+
+    /// Returns `Some(&T)` if the cell has been initialized, `None` otherwise
+    pub fn get(&self) -> Option<&T> {
+        if let State::Evaluated(ref val) = *unsafe { &*self.inner.get() } {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `Some(&mut T)` if the cell has been initialized, `None` otherwise
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if let State::Evaluated(ref mut val) = *self.inner.get_mut() {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `Some(&mut T)` if the cell has been initialized, `None` otherwise, without
+    /// requiring exclusive access.
+    ///
+    /// Exists so `Lazy::get_maybe_mut` can keep its existing `&self` signature without
+    /// reaching into this cell's private representation; callers are responsible for not
+    /// aliasing the returned reference.
+    pub(crate) fn get_maybe_mut(&self) -> Option<&mut T> {
+        if let State::Evaluated(ref mut val) = *unsafe { &mut *self.inner.get() } {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if a previous initialization attempt panicked, poisoning the cell.
+    pub fn is_poisoned(&self) -> bool {
+        matches!(*unsafe { &*self.inner.get() }, State::Poisoned)
+    }
+
+    /// Clears any stored value or poison, returning the cell to its unevaluated state.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the evaluation is already in progress.
+    pub fn reset(&self) {
+        unsafe {
+            match *self.inner.get() {
+                State::InProgress => panic!("Lazy evaluation called from itself."),
+                _ => *self.inner.get() = State::Unevaluated
+            }
+        }
+    }
+
+    /// Consumes the cell, returning its value if it was initialized.
+    pub fn into_inner(self) -> Option<T> {
+        if let State::Evaluated(val) = self.inner.into_inner() {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    /// Takes the value out, leaving the cell unevaluated.
+    ///
+    /// If the cell was poisoned, it remains poisoned and `None` is returned.
+    pub fn take(&mut self) -> Option<T> {
+        let state = mem::replace(self.inner.get_mut(), State::Unevaluated);
+
+        if let State::Evaluated(val) = state {
+            Some(val)
+        } else {
+            *self.inner.get_mut() = state;
+            None
+        }
+    }
+
+    /// Returns the cell's value, initializing it via `<T as LazyInit<T, E>>::init()` if it
+    /// hasn't run yet.
+    ///
+    /// If a previous initialization attempt failed, the cell is left unevaluated and a later
+    /// call retries `init()`. If a previous attempt panicked, the cell is poisoned and this
+    /// panics with a "previously poisoned" message instead of retrying.
+    #[inline(never)]
+    pub fn get_or_try_init<E>(&self) -> Result<&T, E> where T: LazyInit<T, E> {
+        unsafe {
+            match *self.inner.get() {
+                State::Evaluated(_) => {},
+                State::InProgress => panic!("Lazy evaluation called from itself."),
+                State::Poisoned => panic!("Lazy instance previously poisoned during initialization."),
+                State::Unevaluated => {
+                    match ptr::replace(self.inner.get(), State::InProgress) {
+                        State::Unevaluated => {
+                            // Guards against a panic unwinding out of `init()`: if the guard
+                            // is dropped before being disarmed, the cell is left `Poisoned`
+                            // instead of stuck `InProgress` forever.
+                            struct PoisonGuard<'a, T>(&'a UnsafeCell<State<T>>, bool);
+
+                            impl<'a, T> Drop for PoisonGuard<'a, T> {
+                                fn drop(&mut self) {
+                                    if !self.1 {
+                                        unsafe { *self.0.get() = State::Poisoned; }
+                                    }
+                                }
+                            }
+
+                            let mut guard = PoisonGuard(&self.inner, false);
+
+                            match <T as LazyInit<T, E>>::init() {
+                                Ok(value) => {
+                                    *self.inner.get() = State::Evaluated(value);
+                                    guard.1 = true;
+                                },
+                                Err(e) => {
+                                    *self.inner.get() = State::Unevaluated;
+                                    guard.1 = true;
+                                    return Err(e);
+                                }
+                            }
+                        },
+                        _ => unreachable!()
+                    }
+                }
+            }
+
+            if let State::Evaluated(ref val) = *self.inner.get() {
+                return Ok(val);
+            }
+
+            unreachable!()
+        }
+    }
 }
 
 /// Lazy data structure
 pub struct Lazy<T, E = ()> {
-    inner: UnsafeCell<State<T>>,
+    cell: OnceCell<T>,
     _error_marker: PhantomData<E>
 }
 
@@ -46,7 +237,7 @@ impl<T, E> Lazy<T, E> {
     #[inline(always)]
     pub fn new() -> Lazy<T, E> {
         Lazy {
-            inner: UnsafeCell::new(State::Unevaluated),
+            cell: OnceCell::new(),
             _error_marker: PhantomData
         }
     }
@@ -57,75 +248,209 @@ impl<T, E> Lazy<T, E> {
     ///
     /// Panics if the evaluation is already in progress.
     pub unsafe fn set(&self, value: T) {
-        match *self.inner.get() {
-            State::InProgress => { panic!("Lazy evaluation called from itself."); }
-            _ => { *self.inner.get() = State::Evaluated(value) }
-        }
+        self.cell.force_set(value);
     }
 
     /// Returns `Some(&T)` if the instance has been evaluated, `None` otherwise
     pub fn get_maybe(&self) -> Option<&T> {
-        if let State::Evaluated(ref val) = *unsafe { &*self.inner.get() } {
-            Some(val)
-        } else {
-            None
-        }
+        self.cell.get()
     }
 
     /// Returns `Some(&mut T)` if the instance has been evaluated, `None` otherwise
     pub fn get_maybe_mut(&self) -> Option<&mut T> {
-        if let State::Evaluated(ref mut val) = *unsafe { &mut *self.inner.get() } {
-            Some(val)
-        } else {
-            None
-        }
+        self.cell.get_maybe_mut()
     }
-}
 
-impl<T, E> Lazy<T, E> where T: LazyInit<T, E> {
-    #[inline(never)]
-    fn evaluate(&self) -> Result<(), E> {
-        unsafe {
-            match *self.inner.get() {
-                State::Evaluated(_) => return Ok(()),
-                State::InProgress => panic!("Lazy evaluation called from itself."),
-                _ => {}
-            }
+    /// Returns `true` if a previous initialization attempt panicked, poisoning the instance.
+    pub fn is_poisoned(&self) -> bool {
+        self.cell.is_poisoned()
+    }
 
-            match ptr::replace(self.inner.get(), State::InProgress) {
-                State::Unevaluated => {
-                    *self.inner.get() = State::Evaluated(<T as LazyInit<T, E>>::init()?);
-                },
-                _ => unreachable!()
-            }
-        }
+    /// Clears any evaluated value or poison, returning the instance to its unevaluated state.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the evaluation is already in progress.
+    pub fn reset(&self) {
+        self.cell.reset()
+    }
 
-        Ok(())
+    /// Consumes the instance, returning the evaluated value if it was computed.
+    pub fn into_inner(self) -> Option<T> {
+        self.cell.into_inner()
     }
 
+    /// Takes the evaluated value out, leaving the instance unevaluated.
+    ///
+    /// If the instance was poisoned, it remains poisoned and `None` is returned.
+    pub fn take(&mut self) -> Option<T> {
+        self.cell.take()
+    }
+}
+
+impl<T, E> Lazy<T, E> where T: LazyInit<T, E> {
     /// Evaluates the instance and returns a reference to the result.
     ///
     /// If the instance was already eveluated, the previous value is returned.
     pub fn get(&self) -> Result<&T, E> {
-        self.evaluate()?;
-
-        if let State::Evaluated(ref val) = *unsafe { &*self.inner.get() } {
-            return Ok(val);
-        }
-
-        unreachable!()
+        self.cell.get_or_try_init()
     }
 
     /// Evaluates the instance and returns a mutable reference to the result.
     ///
     /// If the instance was already eveluated, the previous value is returned.
     pub fn get_mut(&mut self) -> Result<&mut T, E> {
-        self.evaluate()?;
+        self.cell.get_or_try_init::<E>()?;
 
-        if let State::Evaluated(ref mut val) = *unsafe { &mut *self.inner.get() } {
-            return Ok(val);
+        Ok(self.cell.get_mut().unwrap_or_else(|| unreachable!()))
+    }
+}
+
+impl<T> Deref for Lazy<T, ()> where T: LazyInit<T, ()> {
+    type Target = T;
+
+    /// Evaluates the instance on first access, then derefs to the result.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `init()` returns `Err(())`. `E = ()` only means errors carry no information,
+    /// not that initialization is guaranteed to succeed.
+    fn deref(&self) -> &T {
+        self.get().expect("Lazy initialization failed")
+    }
+}
+
+impl<T> DerefMut for Lazy<T, ()> where T: LazyInit<T, ()> {
+    /// Evaluates the instance on first access, then derefs to the result.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `init()` returns `Err(())`. `E = ()` only means errors carry no information,
+    /// not that initialization is guaranteed to succeed.
+    fn deref_mut(&mut self) -> &mut T {
+        self.get_mut().expect("Lazy initialization failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug)]
+    struct FlakyThing(u32);
+
+    impl LazyInit<FlakyThing, &'static str> for FlakyThing {
+        fn init() -> Result<FlakyThing, &'static str> {
+            if ATTEMPTS.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err("boom")
+            } else {
+                Ok(FlakyThing(99))
+            }
         }
+    }
+
+    #[test]
+    fn failed_init_can_be_retried() {
+        let lazy: Lazy<FlakyThing, &'static str> = Lazy::new();
+
+        assert_eq!(lazy.get().unwrap_err(), "boom");
+        assert!(!lazy.is_poisoned());
+        assert_eq!(lazy.get().unwrap().0, 99);
+    }
+
+    #[derive(Debug)]
+    struct PanicsThing;
+
+    impl LazyInit<PanicsThing> for PanicsThing {
+        fn init() -> Result<PanicsThing, ()> {
+            panic!("deliberate panic for test");
+        }
+    }
+
+    #[test]
+    fn panicking_init_poisons_and_later_get_reports_poison() {
+        let lazy: Lazy<PanicsThing, ()> = Lazy::new();
 
-        unreachable!()
+        let first = panic::catch_unwind(panic::AssertUnwindSafe(|| lazy.get()));
+        assert!(first.is_err());
+        assert!(lazy.is_poisoned());
+
+        let second = panic::catch_unwind(panic::AssertUnwindSafe(|| lazy.get()));
+        let message = *second.unwrap_err().downcast::<&str>().unwrap();
+        assert_eq!(message, "Lazy instance previously poisoned during initialization.");
     }
-}
\ No newline at end of file
+
+    #[derive(Debug)]
+    struct FailsThing;
+
+    impl LazyInit<FailsThing> for FailsThing {
+        fn init() -> Result<FailsThing, ()> {
+            Err(())
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Lazy initialization failed")]
+    fn deref_panics_on_failed_init_instead_of_claiming_unreachable() {
+        let lazy: Lazy<FailsThing, ()> = Lazy::new();
+        let _ = *lazy;
+    }
+
+    #[derive(Debug)]
+    struct Simple(u32);
+
+    impl LazyInit<Simple> for Simple {
+        fn init() -> Result<Simple, ()> {
+            Ok(Simple(99))
+        }
+    }
+
+    #[test]
+    fn into_inner_returns_value_only_if_evaluated() {
+        let unevaluated: Lazy<Simple> = Lazy::new();
+        assert!(unevaluated.into_inner().is_none());
+
+        let evaluated: Lazy<Simple> = Lazy::new();
+        assert_eq!(evaluated.get().unwrap().0, 99);
+        assert_eq!(evaluated.into_inner().unwrap().0, 99);
+    }
+
+    #[test]
+    fn take_clears_the_instance_but_leaves_poison_in_place() {
+        let mut lazy: Lazy<Simple> = Lazy::new();
+        assert_eq!(lazy.get().unwrap().0, 99);
+
+        assert_eq!(lazy.take().unwrap().0, 99);
+        assert!(lazy.get_maybe().is_none());
+
+        let lazy: Lazy<PanicsThing, ()> = Lazy::new();
+        let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| lazy.get()));
+        assert!(lazy.is_poisoned());
+
+        let mut lazy = lazy;
+        assert!(lazy.take().is_none());
+        assert!(lazy.is_poisoned());
+    }
+
+    #[test]
+    fn once_cell_set_succeeds_once_then_fails() {
+        let cell: OnceCell<u32> = OnceCell::new();
+
+        assert_eq!(cell.set(1), Ok(()));
+        assert_eq!(cell.get(), Some(&1));
+        assert_eq!(cell.set(2), Err(2));
+        assert_eq!(cell.get(), Some(&1));
+    }
+
+    #[test]
+    fn once_cell_get_or_try_init_uses_a_value_supplied_via_set() {
+        let cell: OnceCell<Simple> = OnceCell::new();
+
+        cell.set(Simple(5)).unwrap();
+        assert_eq!(cell.get_or_try_init::<()>().unwrap().0, 5);
+    }
+}
@@ -0,0 +1,194 @@
+//! Thread-safe companion to [`Lazy`](crate::Lazy), synchronized with [`std::sync::Mutex`].
+
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+use crate::{LazyInit, State};
+
+/// Thread-safe lazy data structure.
+///
+/// Unlike [`Lazy`](crate::Lazy), `SyncLazy` may be shared across threads, including from a
+/// `static`: the first thread to call `get`/`get_mut` runs `<T as LazyInit<T, E>>::init()`
+/// while every other thread blocks until it completes, after which all threads observe the
+/// same `&T`. As with `Lazy`, a failed `init()` leaves the instance unevaluated so a later
+/// call can retry, and a panic inside `init()` poisons the instance instead of locking it up
+/// forever.
+pub struct SyncLazy<T, E = ()> {
+    state: Mutex<State<T>>,
+    _error_marker: PhantomData<E>
+}
+
+unsafe impl<T: Sync + Send, E: Sync + Send> Sync for SyncLazy<T, E> {}
+
+impl<T, E> Default for SyncLazy<T, E> {
+    #[inline(always)]
+    fn default() -> SyncLazy<T, E> {
+        SyncLazy::new()
+    }
+}
+
+impl<T, E> SyncLazy<T, E> {
+    /// Create a new uninitialized lazy instance.
+    #[inline(always)]
+    pub fn new() -> SyncLazy<T, E> {
+        SyncLazy {
+            state: Mutex::new(State::Unevaluated),
+            _error_marker: PhantomData
+        }
+    }
+
+    /// Returns `Some(&T)` if the instance has been evaluated, `None` otherwise
+    pub fn get_maybe(&self) -> Option<&T> {
+        let guard = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let State::Evaluated(ref val) = *guard {
+            let ptr: *const T = val;
+            drop(guard);
+            Some(unsafe { &*ptr })
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if a previous initialization attempt panicked, poisoning the instance.
+    pub fn is_poisoned(&self) -> bool {
+        let guard = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        matches!(*guard, State::Poisoned)
+    }
+
+    /// Clears any evaluated value or poison, returning the instance to its unevaluated state.
+    pub fn reset(&self) {
+        let mut guard = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *guard = State::Unevaluated;
+    }
+}
+
+/// Runs `init()` if `state` is still `Unevaluated`, leaving it `Evaluated` on success,
+/// `Unevaluated` on `Err` (so a later call retries), or `Poisoned` if `init()` panics.
+fn run_init<T, E>(state: &mut State<T>) -> Result<(), E> where T: LazyInit<T, E> {
+    match *state {
+        State::Evaluated(_) => return Ok(()),
+        State::Poisoned => panic!("Lazy instance previously poisoned during initialization."),
+        State::InProgress => unreachable!("SyncLazy never stores a mid-init state across calls"),
+        State::Unevaluated => {}
+    }
+
+    struct PoisonGuard<'a, T>(&'a mut State<T>, bool);
+
+    impl<'a, T> Drop for PoisonGuard<'a, T> {
+        fn drop(&mut self) {
+            if !self.1 {
+                *self.0 = State::Poisoned;
+            }
+        }
+    }
+
+    let mut guard = PoisonGuard(state, false);
+
+    match <T as LazyInit<T, E>>::init() {
+        Ok(value) => {
+            *guard.0 = State::Evaluated(value);
+            guard.1 = true;
+            Ok(())
+        },
+        Err(e) => {
+            *guard.0 = State::Unevaluated;
+            guard.1 = true;
+            Err(e)
+        }
+    }
+}
+
+impl<T, E> SyncLazy<T, E> where T: LazyInit<T, E> {
+    /// Evaluates the instance and returns a reference to the result.
+    ///
+    /// If the instance was already evaluated, the previous value is returned. A failed
+    /// `init()` leaves the instance unevaluated so a later call can retry.
+    pub fn get(&self) -> Result<&T, E> {
+        let mut guard = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        run_init(&mut guard)?;
+
+        if let State::Evaluated(ref val) = *guard {
+            let ptr: *const T = val;
+            drop(guard);
+            return Ok(unsafe { &*ptr });
+        }
+
+        unreachable!()
+    }
+
+    /// Evaluates the instance and returns a mutable reference to the result.
+    ///
+    /// If the instance was already evaluated, the previous value is returned. A failed
+    /// `init()` leaves the instance unevaluated so a later call can retry.
+    pub fn get_mut(&mut self) -> Result<&mut T, E> {
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        run_init(state)?;
+
+        if let State::Evaluated(ref mut val) = *state {
+            return Ok(val);
+        }
+
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    static INIT_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Thing(u32);
+
+    impl LazyInit<Thing> for Thing {
+        fn init() -> Result<Thing, ()> {
+            INIT_CALLS.fetch_add(1, Ordering::SeqCst);
+            Ok(Thing(7))
+        }
+    }
+
+    #[test]
+    fn concurrent_get_runs_init_exactly_once() {
+        let lazy = Arc::new(SyncLazy::<Thing>::new());
+
+        let handles: Vec<_> = (0..8).map(|_| {
+            let lazy = Arc::clone(&lazy);
+            thread::spawn(move || lazy.get().unwrap().0)
+        }).collect();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 7);
+        }
+
+        assert_eq!(INIT_CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug)]
+    struct FlakyThing(u32);
+
+    impl LazyInit<FlakyThing, &'static str> for FlakyThing {
+        fn init() -> Result<FlakyThing, &'static str> {
+            if ATTEMPTS.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err("boom")
+            } else {
+                Ok(FlakyThing(99))
+            }
+        }
+    }
+
+    #[test]
+    fn failed_init_can_be_retried() {
+        let lazy: SyncLazy<FlakyThing, &'static str> = SyncLazy::new();
+
+        assert_eq!(lazy.get().unwrap_err(), "boom");
+        assert!(!lazy.is_poisoned());
+        assert_eq!(lazy.get().unwrap().0, 99);
+    }
+}